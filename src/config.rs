@@ -0,0 +1,113 @@
+use std::{fs, path::Path};
+
+use serde::Deserialize;
+
+use crate::{schedule::Schedule, telemetry::TelemetryConfig, Error};
+
+fn default_poll_interval_secs() -> u64 {
+    60
+}
+
+fn default_turn_on_threshold() -> i64 {
+    1500
+}
+
+fn default_turn_off_threshold() -> i64 {
+    1200
+}
+
+fn default_min_ampere() -> u8 {
+    6
+}
+
+fn default_max_ampere() -> u8 {
+    16
+}
+
+fn default_phase_min_watts() -> i64 {
+    3 * 230 * 6
+}
+
+fn default_serial_device() -> String {
+    "/dev/serial0".to_string()
+}
+
+fn default_gpio_pin() -> u8 {
+    2
+}
+
+fn default_averaging_window() -> usize {
+    5
+}
+
+fn default_max_retries() -> u32 {
+    3
+}
+
+fn default_max_consecutive_meter_failures() -> u32 {
+    3
+}
+
+/// Runtime-tunable settings for the charging controller, deserialized
+/// from a TOML file. Loaded at startup from the path given on the
+/// command line or the `METER2CAR_CONFIG` environment variable, and
+/// reloadable on `SIGHUP` so thresholds can be tuned against a running
+/// charge session without restarting the daemon.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Config {
+    #[serde(default = "default_poll_interval_secs")]
+    pub poll_interval_secs: u64,
+    #[serde(default = "default_turn_on_threshold")]
+    pub turn_on_threshold: i64,
+    #[serde(default = "default_turn_off_threshold")]
+    pub turn_off_threshold: i64,
+    #[serde(default = "default_min_ampere")]
+    pub min_ampere: u8,
+    #[serde(default = "default_max_ampere")]
+    pub max_ampere: u8,
+    /// Minimum power (in watts) needed to sustain the 3-phase 6 A
+    /// minimum charging current; used by the phase-switching logic.
+    #[serde(default = "default_phase_min_watts")]
+    pub phase_min_watts: i64,
+    #[serde(default = "default_serial_device")]
+    pub serial_device: String,
+    #[serde(default = "default_gpio_pin")]
+    pub gpio_pin: u8,
+    /// Number of ticks averaged over by the running-average smoothers.
+    #[serde(default = "default_averaging_window")]
+    pub averaging_window: usize,
+    /// Optional `[telemetry]` table enabling MQTT telemetry publishing.
+    /// Absent means telemetry is disabled.
+    pub telemetry: Option<TelemetryConfig>,
+    /// Number of attempts (including the first) made for a transient
+    /// meter read or Go-e request before giving up on that tick.
+    #[serde(default = "default_max_retries")]
+    pub max_retries: u32,
+    /// Number of consecutive ticks the meter may fail to read before
+    /// charging is disabled as a safety fallback.
+    #[serde(default = "default_max_consecutive_meter_failures")]
+    pub max_consecutive_meter_failures: u32,
+    /// Time-of-day windows (solar-only/boost/blackout) that override
+    /// the default surplus-driven behavior.
+    #[serde(default)]
+    pub schedule: Schedule,
+    /// Path of the unix domain socket that accepts manual
+    /// force-charge/force-off/clear override commands. Absent disables
+    /// the socket.
+    #[serde(default)]
+    pub override_socket_path: Option<String>,
+}
+
+impl Config {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let contents = fs::read_to_string(path).map_err(Error::Io)?;
+        toml::from_str(&contents).map_err(Error::TomlParse)
+    }
+
+    /// Resolves the config file path from the given command line
+    /// argument, falling back to the `METER2CAR_CONFIG` environment
+    /// variable.
+    pub fn path_from_env(cli_arg: Option<String>) -> Option<String> {
+        cli_arg.or_else(|| std::env::var("METER2CAR_CONFIG").ok())
+    }
+}
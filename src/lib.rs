@@ -10,6 +10,17 @@ use serde_json::{Map, Value};
 use serialport::{DataBits, Parity, SerialPort, StopBits};
 use smart_meter::SmartMeter;
 
+mod config;
+mod ntp_check;
+mod schedule;
+mod socket_control;
+mod telemetry;
+
+pub use config::Config;
+pub use schedule::{tick_mode, Mode, Schedule};
+pub use socket_control::serve_override_socket;
+pub use telemetry::{Telemetry, TelemetryConfig, TelemetrySample};
+
 #[derive(Debug)]
 pub enum Error {
     Io(io::Error),
@@ -18,6 +29,9 @@ pub enum Error {
     Gpio(rppal::gpio::Error),
     UrlError(url::ParseError),
     Reqwest(reqwest::Error),
+    TomlParse(toml::de::Error),
+    Mqtt(rumqttc::ClientError),
+    MqttConnection(rumqttc::ConnectionError),
     InvalidApduFormat,
     InvalidStatusJson,
     FailedRequest,
@@ -32,6 +46,9 @@ impl fmt::Display for Error {
             Error::Gpio(err) => err.fmt(f),
             Error::UrlError(err) => err.fmt(f),
             Error::Reqwest(err) => err.fmt(f),
+            Error::TomlParse(err) => err.fmt(f),
+            Error::Mqtt(err) => err.fmt(f),
+            Error::MqttConnection(err) => err.fmt(f),
             Error::InvalidApduFormat => write!(f, "invalid apdu format"),
             Error::InvalidStatusJson => write!(f, "invalid JSON status"),
             Error::FailedRequest => write!(f, "failed request"),
@@ -48,6 +65,9 @@ impl error::Error for Error {
             Error::Gpio(err) => Some(err),
             Error::UrlError(err) => Some(err),
             Error::Reqwest(err) => Some(err),
+            Error::TomlParse(err) => Some(err),
+            Error::Mqtt(err) => Some(err),
+            Error::MqttConnection(err) => Some(err),
             _ => None,
         }
     }
@@ -190,6 +210,23 @@ impl GoEStatus {
     }
 }
 
+/// The number of phases the Go-e charger draws current on, controlled
+/// via its `psm` parameter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PhaseMode {
+    Single,
+    Three,
+}
+
+impl PhaseMode {
+    fn psm_value(self) -> u8 {
+        match self {
+            PhaseMode::Single => 1,
+            PhaseMode::Three => 2,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct GoE {
     url: Url,
@@ -242,31 +279,47 @@ impl GoE {
         };
         self.send_message(query).await
     }
-    pub async fn set_ampere(&self, ampere: u8) -> Result<(), Error> {
-        let ampere = ampere.clamp(6, 14);
+    /// Sets the charging current, clamped to `[min_ampere, max_ampere]`
+    /// (the configured [`Config::min_ampere`]/[`Config::max_ampere`]
+    /// bounds, not a fixed range) so no caller can push the charger
+    /// outside the range the user configured.
+    pub async fn set_ampere(
+        &self,
+        ampere: u8,
+        min_ampere: u8,
+        max_ampere: u8,
+    ) -> Result<(), Error> {
+        let ampere = ampere.clamp(min_ampere, max_ampere);
         let query = format!("payload=amx={}", ampere);
         self.send_message(&query).await
     }
+    pub async fn set_phase_mode(&self, mode: PhaseMode) -> Result<(), Error> {
+        let query = format!("payload=psm={}", mode.psm_value());
+        self.send_message(&query).await
+    }
 }
 
 #[derive(Debug)]
-pub struct RunningAverage<const N: usize> {
-    values: [i64; N],
+pub struct RunningAverage {
+    values: Vec<i64>,
     current_position: usize,
     initialized: bool,
 }
 
-impl<const N: usize> RunningAverage<N> {
-    pub fn new() -> Self {
+impl RunningAverage {
+    /// Creates a running average over the given window length (in
+    /// ticks). The window length comes from [`Config::averaging_window`]
+    /// and is fixed for the lifetime of the average.
+    pub fn new(window: usize) -> Self {
         Self {
-            values: [0; N],
+            values: vec![0; window.max(1)],
             current_position: 0,
             initialized: false,
         }
     }
 
     fn init(&mut self, value: i64) {
-        self.values = [value; N];
+        self.values.fill(value);
         self.initialized = true;
     }
 
@@ -281,9 +334,101 @@ impl<const N: usize> RunningAverage<N> {
     pub fn add(&mut self, value: i64) {
         if self.initialized {
             self.values[self.current_position] = value;
-            self.current_position = (self.current_position + 1) % N;
+            self.current_position = (self.current_position + 1) % self.values.len();
         } else {
             self.init(value)
         }
     }
 }
+
+/// A PID controller that regulates the charging ampere to hold the net
+/// grid power at `setpoint` (negative grid power means export).
+///
+/// The output is clamped to `[min_amp, max_amp]`, and the integral term
+/// is frozen whenever the output saturates so it cannot wind up while
+/// the charger is already at its limit.
+#[derive(Debug)]
+pub struct Pid {
+    pub kp: f64,
+    pub ki: f64,
+    pub kd: f64,
+    pub setpoint: f64,
+    pub min_amp: f64,
+    pub max_amp: f64,
+    integral: f64,
+    e_prev: Option<f64>,
+    last_p_term: f64,
+    last_i_term: f64,
+    last_d_term: f64,
+}
+
+impl Pid {
+    pub fn new(kp: f64, ki: f64, kd: f64, setpoint: f64, min_amp: f64, max_amp: f64) -> Self {
+        Self {
+            kp,
+            ki,
+            kd,
+            setpoint,
+            min_amp,
+            max_amp,
+            integral: 0.0,
+            e_prev: None,
+            last_p_term: 0.0,
+            last_i_term: 0.0,
+            last_d_term: 0.0,
+        }
+    }
+
+    /// Clears the integral and derivative history. Call this whenever
+    /// charging is disabled so the next activation starts from a clean
+    /// state instead of resuming with stale error history.
+    pub fn reset(&mut self) {
+        self.integral = 0.0;
+        self.e_prev = None;
+        self.last_p_term = 0.0;
+        self.last_i_term = 0.0;
+        self.last_d_term = 0.0;
+    }
+
+    /// The individual `(p, i, d)` contributions of the last [`Pid::update`]
+    /// call, for telemetry and debugging.
+    pub fn last_terms(&self) -> (f64, f64, f64) {
+        (self.last_p_term, self.last_i_term, self.last_d_term)
+    }
+
+    /// Advances the controller by `dt` seconds given the current grid
+    /// power and returns the new ampere output, clamped to
+    /// `[min_amp, max_amp]`.
+    pub fn update(&mut self, grid_power: f64, dt: f64) -> f64 {
+        let e = self.setpoint - grid_power;
+        let derivative = match self.e_prev {
+            Some(e_prev) => (e - e_prev) / dt,
+            None => 0.0,
+        };
+
+        let unclamped_integral = self.integral + e * dt;
+        let p_term = self.kp * e;
+        let d_term = self.kd * derivative;
+        let unclamped_output = p_term + self.ki * unclamped_integral + d_term;
+
+        let (output, i_term) = if unclamped_output > self.max_amp || unclamped_output < self.min_amp
+        {
+            // Saturated: freeze the integral at its previous value so it
+            // doesn't keep accumulating while the output can't follow.
+            let i_term = self.ki * self.integral;
+            (
+                (p_term + i_term + d_term).clamp(self.min_amp, self.max_amp),
+                i_term,
+            )
+        } else {
+            self.integral = unclamped_integral;
+            (unclamped_output, self.ki * unclamped_integral)
+        };
+
+        self.e_prev = Some(e);
+        self.last_p_term = p_term;
+        self.last_i_term = i_term;
+        self.last_d_term = d_term;
+        output
+    }
+}
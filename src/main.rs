@@ -1,10 +1,104 @@
-use std::{env, error::Error, thread::sleep, time::Duration};
+use std::{
+    env,
+    error::Error,
+    future::Future,
+    sync::{Arc, Mutex},
+    thread::sleep,
+    time::Duration,
+};
 
 use hex::FromHex;
-use meter2car::{ChargingStatus, GoE, Meter, RunningAverage};
+use meter2car::{
+    serve_override_socket, tick_mode, ChargingStatus, Config, GoE, Meter, Mode, PhaseMode, Pid,
+    RunningAverage, Telemetry, TelemetrySample,
+};
+use tokio::signal::unix::{signal, SignalKind};
 
-const TURN_ON_THRESHOLD: i64 = 1500;
-const TURN_OFF_THRESHOLD: i64 = 1200;
+/// Errors that are worth retrying: a hiccup on the serial line or the
+/// HTTP connection to the Go-e, as opposed to a fatal protocol/parsing
+/// error that retrying cannot fix.
+fn is_transient(err: &meter2car::Error) -> bool {
+    matches!(
+        err,
+        meter2car::Error::Io(_) | meter2car::Error::SerialPort(_) | meter2car::Error::Reqwest(_)
+    )
+}
+
+/// Retries `f` with exponential backoff (capped at 30 s) while it keeps
+/// returning transient errors, up to `max_attempts` tries in total. A
+/// fatal error, or the last transient one, is returned as-is so the
+/// caller can decide how to degrade.
+async fn retry_with_backoff<T, F, Fut>(max_attempts: u32, mut f: F) -> Result<T, meter2car::Error>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, meter2car::Error>>,
+{
+    let mut backoff = Duration::from_secs(1);
+    for attempt in 1..=max_attempts.max(1) {
+        match f().await {
+            Ok(value) => return Ok(value),
+            Err(err) if attempt < max_attempts && is_transient(&err) => {
+                eprintln!(
+                    "transient error on attempt {}/{}, retrying in {:?}: {}",
+                    attempt, max_attempts, backoff, err
+                );
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(Duration::from_secs(30));
+            }
+            Err(err) => return Err(err),
+        }
+    }
+    unreachable!("loop always returns on the last attempt")
+}
+
+/// Retries the (synchronous, `&mut self`) meter read with the same
+/// backoff policy as [`retry_with_backoff`]. `Meter::available_power`
+/// can't be threaded through that generic helper: its `&mut Meter`
+/// borrow would have to escape the `FnMut` closure body, which doesn't
+/// compile. This is a dedicated loop instead.
+fn retry_meter_read(meter: &mut Meter, max_attempts: u32) -> Result<i32, meter2car::Error> {
+    let mut backoff = Duration::from_secs(1);
+    for attempt in 1..=max_attempts.max(1) {
+        match meter.available_power() {
+            Ok(value) => return Ok(value),
+            Err(err) if attempt < max_attempts && is_transient(&err) => {
+                eprintln!(
+                    "transient error on attempt {}/{}, retrying in {:?}: {}",
+                    attempt, max_attempts, backoff, err
+                );
+                sleep(backoff);
+                backoff = (backoff * 2).min(Duration::from_secs(30));
+            }
+            Err(err) => return Err(err),
+        }
+    }
+    unreachable!("loop always returns on the last attempt")
+}
+
+// Number of consecutive ticks the surplus must sit on the wrong side of
+// the threshold before switching phases, so a brief dip or spike doesn't
+// flap.
+const PHASE_SWITCH_HYSTERESIS_TICKS: u32 = 3;
+// Minimum number of ticks between phase switches (a 1 minute tick means
+// this is a 10 minute cooldown).
+const PHASE_SWITCH_COOLDOWN_TICKS: u32 = 10;
+// Extra surplus (in watts) required above `phase_min_watts` before
+// switching back up to 3-phase, so the drop-to-1-phase and
+// back-to-3-phase thresholds aren't the same value. Without this gap the
+// surplus can sit right on `phase_min_watts` and flip phases every time
+// it crosses, even with the tick debounce above.
+const PHASE_SWITCH_HYSTERESIS_MARGIN_WATTS: i64 = 3 * 230;
+
+fn config_path() -> String {
+    Config::path_from_env(env::args().nth(2))
+        .expect("Expected config path as second argument or METER2CAR_CONFIG")
+}
+
+/// Loads the config at startup. Panics on a missing path or malformed
+/// file, since there's no previous config to fall back to yet.
+fn load_config(path: &str) -> Config {
+    Config::load(path).expect("Failed to load config")
+}
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
@@ -14,74 +108,370 @@ async fn main() -> Result<(), Box<dyn Error>> {
     let key = env::var("METER2CAR_KEY")?;
     let key = <[u8; 16]>::from_hex(key).expect("Invalid key format");
 
+    let config_path = config_path();
+    let config = Arc::new(Mutex::new(load_config(&config_path)));
+    let schedule = Arc::new(Mutex::new(config.lock().unwrap().schedule.clone()));
+    {
+        let config = Arc::clone(&config);
+        let schedule = Arc::clone(&schedule);
+        let config_path = config_path.clone();
+        tokio::spawn(async move {
+            let mut sighup =
+                signal(SignalKind::hangup()).expect("failed to register SIGHUP handler");
+            loop {
+                sighup.recv().await;
+                println!("Received SIGHUP, reloading config");
+                // Unlike the startup load, a bad edit here must not panic:
+                // that would unwind this task and take the SIGHUP handler
+                // with it, leaving the daemon unable to reload for the
+                // rest of its life. Log and keep the previous config
+                // instead.
+                match Config::load(&config_path) {
+                    Ok(new_config) => {
+                        schedule.lock().unwrap().sync_windows(&new_config.schedule);
+                        *config.lock().unwrap() = new_config;
+                    }
+                    Err(err) => {
+                        eprintln!(
+                            "failed to reload config, keeping previous config: {}",
+                            err
+                        );
+                    }
+                }
+            }
+        });
+    }
+    if let Some(socket_path) = config.lock().unwrap().override_socket_path.clone() {
+        let schedule = Arc::clone(&schedule);
+        tokio::spawn(async move {
+            if let Err(err) = serve_override_socket(&socket_path, schedule).await {
+                eprintln!("override socket server stopped: {}", err);
+            }
+        });
+    }
+
     let go_e = GoE::open(&url)?;
-    let mut meter = Meter::open("/dev/serial0", key, 2)?;
+    let (serial_device, gpio_pin, averaging_window) = {
+        let config = config.lock().unwrap();
+        (
+            config.serial_device.clone(),
+            config.gpio_pin,
+            config.averaging_window,
+        )
+    };
+    let mut meter = Meter::open(&serial_device, key, gpio_pin)?;
+
+    let telemetry = config
+        .lock()
+        .unwrap()
+        .telemetry
+        .as_ref()
+        .map(Telemetry::connect);
 
     let mut turn_off_counter = 0;
-    let mut power_for_car_runnning_average = RunningAverage::<5>::new();
-    let mut available_power_running_average = RunningAverage::<5>::new();
+    let mut active_averaging_window = averaging_window;
+    let mut power_for_car_runnning_average = RunningAverage::new(active_averaging_window);
+    let mut available_power_running_average = RunningAverage::new(active_averaging_window);
+    let mut pid = Pid::new(0.004, 0.0008, 0.0, 0.0, 0.0, 0.0);
+    let mut low_surplus_ticks = 0;
+    let mut high_surplus_ticks = 0;
+    let mut ticks_since_phase_switch = PHASE_SWITCH_COOLDOWN_TICKS;
+    let mut consecutive_meter_failures = 0;
+    let mut warned_serial_restart_required = false;
 
     loop {
-        let available_power = meter.available_power()?;
+        let config = config.lock().unwrap().clone();
+        pid.min_amp = config.min_ampere as f64;
+        pid.max_amp = config.max_ampere as f64;
+
+        if config.averaging_window != active_averaging_window {
+            println!(
+                "averaging_window changed from {} to {}, resetting running averages",
+                active_averaging_window, config.averaging_window
+            );
+            power_for_car_runnning_average = RunningAverage::new(config.averaging_window);
+            available_power_running_average = RunningAverage::new(config.averaging_window);
+            active_averaging_window = config.averaging_window;
+        }
+        if !warned_serial_restart_required
+            && (config.serial_device != serial_device || config.gpio_pin != gpio_pin)
+        {
+            eprintln!(
+                "serial_device/gpio_pin changed in config, but the meter connection is only \
+                 opened at startup; restart to apply"
+            );
+            warned_serial_restart_required = true;
+        }
+
+        let available_power = match retry_meter_read(&mut meter, config.max_retries) {
+            Ok(available_power) => {
+                consecutive_meter_failures = 0;
+                available_power
+            }
+            Err(err) => {
+                consecutive_meter_failures += 1;
+                eprintln!(
+                    "meter read failed ({} consecutive): {}",
+                    consecutive_meter_failures, err
+                );
+                if consecutive_meter_failures >= config.max_consecutive_meter_failures {
+                    eprintln!(
+                        "meter unreadable for {} consecutive ticks, disabling charging",
+                        consecutive_meter_failures
+                    );
+                    if let Err(err) = go_e.set_charging_allowed(false).await {
+                        eprintln!("failed to disable charging: {}", err);
+                    }
+                }
+                sleep(Duration::from_secs(config.poll_interval_secs));
+                continue;
+            }
+        };
         println!("available power: {}", available_power);
-        let status = go_e.get_status().await?;
+        let status = match retry_with_backoff(config.max_retries, || go_e.get_status()).await {
+            Ok(status) => status,
+            Err(err) => {
+                eprintln!("failed to fetch Go-e status, skipping tick: {}", err);
+                sleep(Duration::from_secs(config.poll_interval_secs));
+                continue;
+            }
+        };
         println!("{:#?}", status);
 
-        if status.is_charging_allowed {
-            if status.charging_status == ChargingStatus::Charging {
-                let power_for_car = available_power + status.total_power as i32;
-                power_for_car_runnning_average.add(power_for_car as i64);
-                let average_power_for_car = power_for_car_runnning_average.get_average();
-                println!(
-                    "average power for car: {}, turn off counter: {}",
-                    average_power_for_car, turn_off_counter
-                );
-                println!(
-                    "TURN_OFF_THRESHOLD: {}",
-                    (TURN_OFF_THRESHOLD * status.phases as i64)
-                );
-                let desired_ampere = average_power_for_car / (230 * status.phases as i64);
-                if turn_off_counter >= 4 {
+        let mut power_for_car = 0i64;
+        let mut desired_ampere = status.ampere as i64;
+
+        available_power_running_average.add(available_power as i64);
+        let average_available_power = available_power_running_average.get_average();
+
+        let mode = tick_mode(&schedule).await;
+        println!("schedule mode: {:?}", mode);
+
+        match mode {
+            Mode::Blackout => {
+                if status.is_charging_allowed {
+                    println!("Blackout window active, disabling charging");
+                    if let Err(err) =
+                        retry_with_backoff(config.max_retries, || go_e.set_charging_allowed(false))
+                            .await
+                    {
+                        eprintln!("failed to disable charging for blackout: {}", err);
+                    }
+                }
+                pid.reset();
+            }
+            Mode::Boost { ampere } => {
+                desired_ampere = ampere as i64;
+                // The target is "until the car's own BMS is done", which
+                // the Go-e reports as `ChargingStatus::Finished` — stop
+                // forcing charging once it gets there instead of holding
+                // it on for the rest of the window.
+                if status.charging_status == ChargingStatus::Finished {
+                    if status.is_charging_allowed {
+                        println!("Boost target reached (car reports finished), disabling charging");
+                        if let Err(err) = retry_with_backoff(config.max_retries, || {
+                            go_e.set_charging_allowed(false)
+                        })
+                        .await
+                        {
+                            eprintln!(
+                                "failed to disable charging after boost target reached: {}",
+                                err
+                            );
+                        }
+                    }
+                } else if !status.is_charging_allowed {
+                    println!("Boost window active, forcing charging at {} A", ampere);
+                    let enable_result: Result<(), meter2car::Error> = async {
+                        retry_with_backoff(config.max_retries, || {
+                            go_e.set_ampere(ampere, config.min_ampere, config.max_ampere)
+                        })
+                        .await?;
+                        sleep(Duration::from_secs(5));
+                        retry_with_backoff(config.max_retries, || go_e.set_charging_allowed(true))
+                            .await
+                    }
+                    .await;
+                    if let Err(err) = enable_result {
+                        eprintln!("failed to force charging on for boost window: {}", err);
+                    }
+                } else if status.ampere != ampere {
+                    if let Err(err) = retry_with_backoff(config.max_retries, || {
+                        go_e.set_ampere(ampere, config.min_ampere, config.max_ampere)
+                    })
+                    .await
+                    {
+                        eprintln!("failed to set boost ampere: {}", err);
+                    }
+                }
+                pid.reset();
+            }
+            Mode::SolarOnly if status.is_charging_allowed => {
+                if status.charging_status == ChargingStatus::Charging {
+                    power_for_car = (available_power + status.total_power as i32) as i64;
+                    power_for_car_runnning_average.add(power_for_car);
+                    let average_power_for_car = power_for_car_runnning_average.get_average();
+                    println!(
+                        "average power for car: {}, turn off counter: {}",
+                        average_power_for_car, turn_off_counter
+                    );
                     println!(
-                        "{} < {}: {}",
-                        average_power_for_car,
-                        (TURN_OFF_THRESHOLD * status.phases as i64),
-                        average_power_for_car < (TURN_OFF_THRESHOLD * status.phases as i64)
+                        "TURN_OFF_THRESHOLD: {}",
+                        (config.turn_off_threshold * status.phases as i64)
                     );
-                    if average_power_for_car < (TURN_OFF_THRESHOLD * status.phases as i64) {
-                        println!("Disable charging");
-                        go_e.set_charging_allowed(false).await?;
-                        power_for_car_runnning_average.deinit();
+                    // Net grid power as seen by the PID: negative means
+                    // power is being exported, i.e. there's still surplus
+                    // left for the car to draw from on top of what it's
+                    // already drawing. Deliberately excludes the car's own
+                    // draw (unlike `average_power_for_car` above, which
+                    // feeds the turn-off check) so the PID tracks actual
+                    // grid import/export rather than total available power.
+                    let grid_power = -(average_available_power as f64);
+                    desired_ampere = pid
+                        .update(grid_power, config.poll_interval_secs as f64)
+                        .round() as i64;
+                    if turn_off_counter >= 4 {
+                        println!(
+                            "{} < {}: {}",
+                            average_power_for_car,
+                            (config.turn_off_threshold * status.phases as i64),
+                            average_power_for_car
+                                < (config.turn_off_threshold * status.phases as i64)
+                        );
+                        if average_power_for_car
+                            < (config.turn_off_threshold * status.phases as i64)
+                        {
+                            println!("Disable charging");
+                            if let Err(err) = retry_with_backoff(config.max_retries, || {
+                                go_e.set_charging_allowed(false)
+                            })
+                            .await
+                            {
+                                eprintln!("failed to disable charging: {}", err);
+                            }
+                            power_for_car_runnning_average.deinit();
+                            pid.reset();
+                        }
+                        turn_off_counter = 0;
                     }
-                    turn_off_counter = 0;
-                }
-                if desired_ampere != status.ampere as i64 {
-                    if desired_ampere < 6 {
-                        println!("desired ampere < 6");
-                    } else if desired_ampere > 16 {
-                        println!("desired ampere > 16");
+                    if desired_ampere != status.ampere as i64 {
+                        if let Err(err) = retry_with_backoff(config.max_retries, || {
+                            go_e.set_ampere(
+                                desired_ampere as u8,
+                                config.min_ampere,
+                                config.max_ampere,
+                            )
+                        })
+                        .await
+                        {
+                            eprintln!("failed to set ampere: {}", err);
+                        }
+                    }
+
+                    ticks_since_phase_switch += 1;
+                    if status.phases == 3 && average_power_for_car < config.phase_min_watts {
+                        low_surplus_ticks += 1;
+                        high_surplus_ticks = 0;
+                    } else if status.phases == 1
+                        && average_power_for_car
+                            >= config.phase_min_watts + PHASE_SWITCH_HYSTERESIS_MARGIN_WATTS
+                    {
+                        high_surplus_ticks += 1;
+                        low_surplus_ticks = 0;
+                    } else {
+                        low_surplus_ticks = 0;
+                        high_surplus_ticks = 0;
+                    }
+                    let new_phase_mode = if status.phases == 3
+                        && low_surplus_ticks >= PHASE_SWITCH_HYSTERESIS_TICKS
+                    {
+                        Some(PhaseMode::Single)
+                    } else if status.phases == 1
+                        && high_surplus_ticks >= PHASE_SWITCH_HYSTERESIS_TICKS
+                    {
+                        Some(PhaseMode::Three)
+                    } else {
+                        None
+                    };
+                    if let Some(phase_mode) = new_phase_mode {
+                        if ticks_since_phase_switch >= PHASE_SWITCH_COOLDOWN_TICKS {
+                            println!("Switching phase mode to {:?}", phase_mode);
+                            let switch_result: Result<(), meter2car::Error> = async {
+                                retry_with_backoff(config.max_retries, || {
+                                    go_e.set_charging_allowed(false)
+                                })
+                                .await?;
+                                sleep(Duration::from_secs(5));
+                                retry_with_backoff(config.max_retries, || {
+                                    go_e.set_phase_mode(phase_mode)
+                                })
+                                .await?;
+                                sleep(Duration::from_secs(5));
+                                retry_with_backoff(config.max_retries, || {
+                                    go_e.set_charging_allowed(true)
+                                })
+                                .await
+                            }
+                            .await;
+                            if let Err(err) = switch_result {
+                                eprintln!("failed to switch phase mode: {}", err);
+                            }
+                            ticks_since_phase_switch = 0;
+                            low_surplus_ticks = 0;
+                            high_surplus_ticks = 0;
+                            pid.reset();
+                        }
                     }
-                    go_e.set_ampere(desired_ampere as u8).await?;
                 }
             }
-        } else {
-            available_power_running_average.add(available_power as i64);
-            let average_available_power = available_power_running_average.get_average();
-            println!("average available power: {}", average_available_power);
-            if status.charging_status == ChargingStatus::Finished
-                || status.charging_status == ChargingStatus::Waiting
-            {
-                if average_available_power > (TURN_ON_THRESHOLD * status.phases as i64) {
-                    let ampere = average_available_power / (230 * status.phases as i64);
-                    println!("Enable charging at {}A", ampere);
-                    go_e.set_ampere(ampere as u8).await?;
-                    sleep(Duration::from_secs(5));
-                    go_e.set_charging_allowed(true).await?;
+            Mode::SolarOnly => {
+                pid.reset();
+                println!("average available power: {}", average_available_power);
+                if status.charging_status == ChargingStatus::Finished
+                    || status.charging_status == ChargingStatus::Waiting
+                {
+                    if average_available_power > (config.turn_on_threshold * status.phases as i64) {
+                        let ampere = average_available_power / (230 * status.phases as i64);
+                        println!("Enable charging at {}A", ampere);
+                        let enable_result: Result<(), meter2car::Error> = async {
+                            retry_with_backoff(config.max_retries, || {
+                                go_e.set_ampere(ampere as u8, config.min_ampere, config.max_ampere)
+                            })
+                            .await?;
+                            sleep(Duration::from_secs(5));
+                            retry_with_backoff(config.max_retries, || {
+                                go_e.set_charging_allowed(true)
+                            })
+                            .await
+                        }
+                        .await;
+                        if let Err(err) = enable_result {
+                            eprintln!("failed to enable charging: {}", err);
+                        }
+                    }
                 }
             }
         }
+
+        if let Some(telemetry) = &telemetry {
+            let (pid_p_term, pid_i_term, pid_d_term) = pid.last_terms();
+            telemetry
+                .publish(&TelemetrySample {
+                    available_power,
+                    power_for_car: power_for_car_runnning_average.get_average(),
+                    ampere: status.ampere,
+                    desired_ampere,
+                    is_charging: status.charging_status == ChargingStatus::Charging,
+                    phases: status.phases,
+                    pid_p_term,
+                    pid_i_term,
+                    pid_d_term,
+                })
+                .await;
+        }
+
         // Wait
-        sleep(Duration::from_secs(60));
+        sleep(Duration::from_secs(config.poll_interval_secs));
         turn_off_counter += 1;
     }
 }
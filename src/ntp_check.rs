@@ -0,0 +1,43 @@
+use std::{
+    net::UdpSocket,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+// Seconds between the NTP epoch (1900-01-01) and the Unix epoch.
+const NTP_UNIX_EPOCH_OFFSET: u64 = 2_208_988_800;
+
+/// Queries `server` (`host:port`, e.g. `"pool.ntp.org:123"`) via a
+/// minimal SNTP request and returns its notion of the current time, or
+/// `None` if the request fails or times out.
+fn query_ntp_time(server: &str) -> Option<SystemTime> {
+    let socket = UdpSocket::bind("0.0.0.0:0").ok()?;
+    socket.set_read_timeout(Some(Duration::from_secs(2))).ok()?;
+    socket.connect(server).ok()?;
+
+    let mut request = [0u8; 48];
+    request[0] = 0x1B; // LI = 0, VN = 3, Mode = 3 (client)
+    socket.send(&request).ok()?;
+
+    let mut response = [0u8; 48];
+    socket.recv(&mut response).ok()?;
+
+    let seconds = u32::from_be_bytes(response[40..44].try_into().ok()?);
+    let unix_seconds = (seconds as u64).checked_sub(NTP_UNIX_EPOCH_OFFSET)?;
+    Some(UNIX_EPOCH + Duration::from_secs(unix_seconds))
+}
+
+/// Checks that the local clock is within `tolerance` of `server` before
+/// the scheduler trusts it for window boundaries. Fails open (returns
+/// `true`) when the NTP query itself fails, since a schedule should
+/// degrade to "trust the local clock" rather than get stuck.
+pub fn clock_is_trustworthy(server: &str, tolerance: Duration) -> bool {
+    let Some(ntp_time) = query_ntp_time(server) else {
+        return true;
+    };
+    let local_time = SystemTime::now();
+    let drift = local_time
+        .duration_since(ntp_time)
+        .or_else(|_| ntp_time.duration_since(local_time))
+        .unwrap_or_default();
+    drift <= tolerance
+}
@@ -0,0 +1,216 @@
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+use chrono::{Local, NaiveTime, Timelike};
+use serde::Deserialize;
+
+use crate::ntp_check;
+
+/// Minimum time between NTP clock checks, so the blocking SNTP round
+/// trip (see [`tick_mode`]) only runs occasionally instead of every
+/// control tick.
+const NTP_CHECK_INTERVAL: Duration = Duration::from_secs(300);
+
+/// The controller mode selected by the active window, or a manual
+/// override.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// Normal surplus-driven PID control; the default outside any
+    /// configured window.
+    SolarOnly,
+    /// Ignore surplus thresholds and charge at a fixed ampere, e.g.
+    /// during a cheap overnight tariff, until the car stops drawing
+    /// power on its own (target reached).
+    Boost { ampere: u8 },
+    /// Charging is disabled regardless of surplus.
+    Blackout,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(tag = "mode", rename_all = "snake_case")]
+enum WindowMode {
+    SolarOnly,
+    Boost { ampere: u8 },
+    Blackout,
+}
+
+impl From<WindowMode> for Mode {
+    fn from(mode: WindowMode) -> Self {
+        match mode {
+            WindowMode::SolarOnly => Mode::SolarOnly,
+            WindowMode::Boost { ampere } => Mode::Boost { ampere },
+            WindowMode::Blackout => Mode::Blackout,
+        }
+    }
+}
+
+fn parse_time_of_day<'de, D>(deserializer: D) -> Result<NaiveTime, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let raw = String::deserialize(deserializer)?;
+    NaiveTime::parse_from_str(&raw, "%H:%M")
+        .map_err(|err| serde::de::Error::custom(format!("invalid time {:?}: {}", raw, err)))
+}
+
+/// A wall-clock window during which `mode` overrides the default
+/// surplus-driven behavior. `end` before `start` wraps past midnight.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ScheduleWindow {
+    #[serde(deserialize_with = "parse_time_of_day")]
+    start: NaiveTime,
+    #[serde(deserialize_with = "parse_time_of_day")]
+    end: NaiveTime,
+    mode: WindowMode,
+}
+
+impl ScheduleWindow {
+    fn contains(&self, time_of_day: NaiveTime) -> bool {
+        if self.start <= self.end {
+            self.start <= time_of_day && time_of_day < self.end
+        } else {
+            time_of_day >= self.start || time_of_day < self.end
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Override {
+    mode: Mode,
+    until: SystemTime,
+}
+
+/// Selects a [`Mode`] for each control tick based on wall-clock windows
+/// configured in TOML, with an optional live manual override that takes
+/// priority over the schedule.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Schedule {
+    #[serde(default)]
+    windows: Vec<ScheduleWindow>,
+    /// NTP server (`host:port`) used to sanity-check the system clock
+    /// before trusting it for window boundaries. `None` disables the
+    /// check and trusts the local clock outright.
+    #[serde(default)]
+    ntp_server: Option<String>,
+    #[serde(skip)]
+    manual_override: Option<Override>,
+    /// Result and timestamp of the last NTP clock check, reused until
+    /// [`NTP_CHECK_INTERVAL`] has passed instead of querying on every
+    /// tick.
+    #[serde(skip)]
+    last_clock_check: Option<(SystemTime, bool)>,
+}
+
+impl Default for Schedule {
+    fn default() -> Self {
+        Self {
+            windows: Vec::new(),
+            ntp_server: None,
+            manual_override: None,
+            last_clock_check: None,
+        }
+    }
+}
+
+impl Schedule {
+    /// Replaces the configured windows and NTP server with `other`'s,
+    /// leaving any live manual override untouched. Used to pick up a
+    /// reloaded config without losing an in-progress override.
+    pub fn sync_windows(&mut self, other: &Schedule) {
+        self.windows = other.windows.clone();
+        self.ntp_server = other.ntp_server.clone();
+    }
+
+    /// Forces `mode` until `until`, overriding the configured windows
+    /// regardless of wall-clock time.
+    pub fn force_override(&mut self, mode: Mode, until: SystemTime) {
+        self.manual_override = Some(Override { mode, until });
+    }
+
+    pub fn clear_override(&mut self) {
+        self.manual_override = None;
+    }
+
+    /// Returns the mode active right now: a live manual override first,
+    /// then the matching configured window, falling back to
+    /// `Mode::SolarOnly` when nothing matches. Does not perform the NTP
+    /// check itself — see [`tick_mode`], which refreshes it first.
+    pub fn current_mode(&mut self) -> Mode {
+        if let Some(over) = self.manual_override {
+            if SystemTime::now() < over.until {
+                return over.mode;
+            }
+            self.manual_override = None;
+        }
+
+        if !self.clock_is_trusted() {
+            return Mode::SolarOnly;
+        }
+
+        let now = Local::now().time().with_nanosecond(0).unwrap_or_default();
+        self.windows
+            .iter()
+            .find(|window| window.contains(now))
+            .map(|window| Mode::from(window.mode))
+            .unwrap_or(Mode::SolarOnly)
+    }
+
+    /// Returns the configured NTP server if its cached trust result is
+    /// missing or older than [`NTP_CHECK_INTERVAL`] and a fresh check is
+    /// due, or `None` if the cache is still fresh (or no `ntp_server` is
+    /// configured). The caller is expected to query it and report the
+    /// result back via [`record_clock_check`](Self::record_clock_check).
+    fn ntp_check_due(&self) -> Option<String> {
+        let server = self.ntp_server.as_ref()?;
+        match self.last_clock_check {
+            Some((checked_at, _))
+                if checked_at.elapsed().unwrap_or_default() < NTP_CHECK_INTERVAL =>
+            {
+                None
+            }
+            _ => Some(server.clone()),
+        }
+    }
+
+    /// Records the outcome of an NTP check requested via
+    /// [`ntp_check_due`](Self::ntp_check_due).
+    fn record_clock_check(&mut self, trusted: bool) {
+        if !trusted {
+            if let Some(server) = &self.ntp_server {
+                eprintln!(
+                    "system clock does not match NTP server {}, ignoring schedule windows",
+                    server
+                );
+            }
+        }
+        self.last_clock_check = Some((SystemTime::now(), trusted));
+    }
+
+    /// Non-blocking read of the last cached NTP check. Trusted by
+    /// default when no `ntp_server` is configured or no check has run
+    /// yet.
+    fn clock_is_trusted(&self) -> bool {
+        match (&self.ntp_server, self.last_clock_check) {
+            (Some(_), Some((_, trusted))) => trusted,
+            _ => true,
+        }
+    }
+}
+
+/// Returns the mode for this tick, refreshing the cached NTP check
+/// first if one is due. The blocking SNTP round trip (up to a couple of
+/// seconds) runs via [`tokio::task::spawn_blocking`] with the schedule
+/// mutex released, so it never stalls the override-socket or
+/// SIGHUP-reload paths that also lock `schedule` while it's in flight.
+pub async fn tick_mode(schedule: &Mutex<Schedule>) -> Mode {
+    let due = schedule.lock().unwrap().ntp_check_due();
+    if let Some(server) = due {
+        let trusted = tokio::task::spawn_blocking(move || {
+            ntp_check::clock_is_trustworthy(&server, Duration::from_secs(5))
+        })
+        .await
+        .unwrap_or(true);
+        schedule.lock().unwrap().record_clock_check(trusted);
+    }
+    schedule.lock().unwrap().current_mode()
+}
@@ -0,0 +1,81 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+use tokio::{
+    io::{AsyncBufReadExt, BufReader},
+    net::UnixListener,
+};
+
+use crate::schedule::{Mode, Schedule};
+
+/// Serves manual override commands on a unix domain socket so charging
+/// can be forced on or off for a bounded duration regardless of the
+/// schedule. Each connection is expected to send exactly one line:
+///
+/// - `force-charge <ampere> <seconds>` — charge at a fixed ampere
+/// - `force-off <seconds>` — disable charging
+/// - `clear` — drop any active override, returning to the schedule
+///
+/// Returns an error if the socket cannot be bound or a connection
+/// cannot be accepted, and otherwise runs forever; the caller should
+/// `tokio::spawn` this.
+pub async fn serve_override_socket(
+    socket_path: &str,
+    schedule: Arc<Mutex<Schedule>>,
+) -> std::io::Result<()> {
+    let _ = std::fs::remove_file(socket_path);
+    let listener = UnixListener::bind(socket_path)?;
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let schedule = Arc::clone(&schedule);
+        tokio::spawn(async move {
+            let mut lines = BufReader::new(stream).lines();
+            match lines.next_line().await {
+                Ok(Some(line)) => apply_command(&schedule, &line),
+                Ok(None) => {}
+                Err(err) => eprintln!("failed to read override command: {}", err),
+            }
+        });
+    }
+}
+
+fn apply_command(schedule: &Arc<Mutex<Schedule>>, line: &str) {
+    let mut parts = line.split_whitespace();
+    match parts.next() {
+        Some("force-charge") => {
+            let ampere = match parts.next().and_then(|arg| arg.parse().ok()) {
+                Some(ampere) => ampere,
+                None => return eprintln!("force-charge requires an ampere argument"),
+            };
+            let seconds = match parts.next().and_then(|arg| arg.parse().ok()) {
+                Some(seconds) => seconds,
+                None => return eprintln!("force-charge requires a duration in seconds"),
+            };
+            let until = SystemTime::now() + Duration::from_secs(seconds);
+            schedule
+                .lock()
+                .unwrap()
+                .force_override(Mode::Boost { ampere }, until);
+            println!("Forced charging at {} A for {} s", ampere, seconds);
+        }
+        Some("force-off") => {
+            let seconds = match parts.next().and_then(|arg| arg.parse().ok()) {
+                Some(seconds) => seconds,
+                None => return eprintln!("force-off requires a duration in seconds"),
+            };
+            let until = SystemTime::now() + Duration::from_secs(seconds);
+            schedule
+                .lock()
+                .unwrap()
+                .force_override(Mode::Blackout, until);
+            println!("Forced charging off for {} s", seconds);
+        }
+        Some("clear") => {
+            schedule.lock().unwrap().clear_override();
+            println!("Cleared manual override");
+        }
+        _ => eprintln!("unrecognized override command: {:?}", line),
+    }
+}
@@ -0,0 +1,94 @@
+use std::time::Duration;
+
+use rumqttc::{AsyncClient, MqttOptions, QoS};
+use serde::{Deserialize, Serialize};
+
+use crate::Error;
+
+/// Config for the optional telemetry publisher. Absent (no `[telemetry]`
+/// table in the config file) means telemetry is disabled.
+#[derive(Debug, Clone, Deserialize)]
+pub struct TelemetryConfig {
+    pub broker_host: String,
+    #[serde(default = "default_broker_port")]
+    pub broker_port: u16,
+    #[serde(default = "default_topic_prefix")]
+    pub topic_prefix: String,
+}
+
+fn default_broker_port() -> u16 {
+    1883
+}
+
+fn default_topic_prefix() -> String {
+    "meter2car".to_string()
+}
+
+/// A single control-loop tick, published as JSON for charting in Home
+/// Assistant / Grafana and for debugging threshold tuning.
+#[derive(Debug, Serialize)]
+pub struct TelemetrySample {
+    pub available_power: i32,
+    pub power_for_car: i64,
+    pub ampere: u8,
+    pub desired_ampere: i64,
+    pub is_charging: bool,
+    pub phases: u8,
+    pub pid_p_term: f64,
+    pub pid_i_term: f64,
+    pub pid_d_term: f64,
+}
+
+/// Publishes [`TelemetrySample`]s to an external MQTT broker. Telemetry
+/// is best-effort: a broker that is unreachable or drops the connection
+/// is logged and otherwise ignored, since losing telemetry must never
+/// stop charging control.
+#[derive(Debug)]
+pub struct Telemetry {
+    client: AsyncClient,
+    topic_prefix: String,
+}
+
+impl Telemetry {
+    pub fn connect(config: &TelemetryConfig) -> Self {
+        let mut mqtt_options =
+            MqttOptions::new("meter2car", config.broker_host.clone(), config.broker_port);
+        mqtt_options.set_keep_alive(Duration::from_secs(30));
+        let (client, mut event_loop) = AsyncClient::new(mqtt_options, 10);
+
+        // Drive the event loop in the background for the lifetime of the
+        // process; connection errors are logged, not propagated.
+        tokio::spawn(async move {
+            loop {
+                if let Err(err) = event_loop.poll().await {
+                    eprintln!("{}", Error::MqttConnection(err));
+                }
+            }
+        });
+
+        Self {
+            client,
+            topic_prefix: config.topic_prefix.clone(),
+        }
+    }
+
+    pub async fn publish(&self, sample: &TelemetrySample) {
+        let payload = match serde_json::to_vec(sample) {
+            Ok(payload) => payload,
+            Err(err) => {
+                eprintln!("failed to serialize telemetry sample: {}", err);
+                return;
+            }
+        };
+        let topic = format!("{}/state", self.topic_prefix);
+        // `try_publish` rather than `publish(..).await`: while the broker
+        // is unreachable the event loop stops draining the bounded request
+        // queue, and awaiting a full queue would stall the control loop.
+        if let Err(err) = self
+            .client
+            .try_publish(topic, QoS::AtMostOnce, false, payload)
+        {
+            eprintln!("{}", Error::Mqtt(err));
+        }
+    }
+}